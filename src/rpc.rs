@@ -15,82 +15,635 @@
 // You should have received a copy of the GNU General Public License
 // along with Axon.  If not, see <http://www.gnu.org/licenses/>.
 
+use fxhash::FxHashMap;
 use futures::{Future, Sink, Stream as FutStream};
 use futures::future::{self, Either};
-use futures::sink::Wait;
-use futures::stream::{SplitSink, SplitStream};
-use futures::sync::mpsc::{self, Receiver, Sender};
-use parking_lot::Mutex;
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use rustls::{self, Certificate, ClientConfig, PrivateKey, RootCertStore};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
 use serde_json;
 use synapse_rpc;
 use synapse_rpc::message::{CMessage, SMessage};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
 use tokio::reactor::{Core, Timeout};
+use tokio::timer::Interval;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::codec::{Framed as CodecFramed, LinesCodec};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+use tokio_rustls::TlsConnector as RustlsConnector;
 use url::Url;
+use webpki::DNSNameRef;
+use webpki_roots;
 use websocket::ClientBuilder;
 use websocket::async::{MessageCodec, Stream};
 use websocket::async::client::Framed;
 use websocket::message::OwnedMessage;
 
-use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
 use std::error::Error;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::BufReader;
+use std::net::ToSocketAddrs;
+#[cfg(windows)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use view::View;
 
 type InnerStream = Framed<Box<Stream + Send>, MessageCodec<OwnedMessage>>;
-type SplitSocket = (
-    RefCell<SplitStream<InnerStream>>,
-    Mutex<Wait<SplitSink<InnerStream>>>,
-);
+// Both the Unix domain socket and the Windows named pipe transports speak the
+// exact same newline-delimited JSON that the WebSocket transport carries
+// inside `OwnedMessage::Text` frames, so once connected they're boxed up into
+// the same `Stream`/`Sink` pair as the WebSocket and the rest of `RpcWorker`
+// never has to know which one it got.
+type BoxedStream = Box<FutStream<Item = OwnedMessage, Error = String> + Send>;
+type BoxedSink = Box<Sink<SinkItem = OwnedMessage, SinkError = String> + Send>;
 
-enum StreamRes {
-    Close,
-    Msg(OwnedMessage),
+/// Endpoint a `RpcWorker` can be connected to.
+///
+/// `ws://`/`wss://` talk to a synapse daemon over the network, `unix://`
+/// connects to a `tokio::net::UnixStream` at the given path, and `pipe://`
+/// connects to a Windows named pipe (`\\.\pipe\<name>`).
+enum Transport {
+    WebSocket(Url),
+    #[cfg(unix)]
+    Unix(String),
+    #[cfg(windows)]
+    Pipe(String),
 }
 
-pub struct RpcContext<'v> {
-    socket: RefCell<Option<SplitSocket>>,
-    waiter: (RefCell<Sender<()>>, RefCell<Receiver<()>>),
+impl Transport {
+    fn parse(url: &Url) -> Result<Transport, String> {
+        match url.scheme() {
+            "ws" | "wss" => Ok(Transport::WebSocket(url.clone())),
+            "unix" => {
+                #[cfg(unix)]
+                {
+                    Ok(Transport::Unix(url.path().to_owned()))
+                }
+                #[cfg(not(unix))]
+                {
+                    Err("unix:// transport is only available on unix".to_owned())
+                }
+            }
+            "pipe" => {
+                #[cfg(windows)]
+                {
+                    let host = url.host_str().unwrap_or(".");
+                    let name = url.path().trim_start_matches('/');
+                    Ok(Transport::Pipe(format!(r"\\{}\pipe\{}", host, name)))
+                }
+                #[cfg(not(windows))]
+                {
+                    Err("pipe:// transport is only available on windows".to_owned())
+                }
+            }
+            other => Err(format!("unsupported transport scheme: {}", other)),
+        }
+    }
+}
+
+// Wraps a raw, newline-delimited-JSON duplex stream (unix socket, named
+// pipe, ...) up as the same boxed `OwnedMessage` stream/sink pair the
+// WebSocket transport produces. There's no framing concept of Ping/Pong/Close
+// at this layer, every frame is just `OwnedMessage::Text`.
+fn box_raw_conn<T>(conn: T) -> (BoxedSink, BoxedStream)
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (sink, stream) = CodecFramed::new(conn, LinesCodec::new()).split();
+
+    let sink: BoxedSink = Box::new(sink.sink_map_err(|err| format!("{}", err)).with(
+        |msg| match msg {
+            OwnedMessage::Text(s) => Ok(s),
+            // There's no framing for Ping/Pong/Close on a raw transport, so
+            // there's nothing sane to write; erroring here is preferable to
+            // serializing a blank line the server would have to reject.
+            other => Err(format!(
+                "cannot send a {:?} frame over a raw transport",
+                other
+            )),
+        },
+    ));
+    // A raw transport has no `Close` frame either, so the peer going away
+    // surfaces only as the underlying stream ending (`None`). Left alone
+    // that's invisible to `drive`'s select loop, which only reacts to
+    // errors, and would otherwise hang forever once the timer stream is the
+    // only thing left feeding it events. Chaining a single terminal error
+    // after the stream ends turns that silent EOF into the same "connection
+    // died" signal every other disconnect path already produces.
+    let stream: BoxedStream = Box::new(
+        stream
+            .map_err(|err| format!("{}", err))
+            .map(OwnedMessage::Text)
+            .chain(::futures::stream::once(Err(
+                "raw transport connection closed".to_owned(),
+            ))),
+    );
+
+    (sink, stream)
+}
+
+#[cfg(windows)]
+fn connect_pipe(path: &str) -> Result<NamedPipeClient, String> {
+    use std::time::Duration as StdDuration;
+
+    // A pipe server that's still servicing the previous client's instance
+    // reports ERROR_PIPE_BUSY rather than blocking; a short retry loop rides
+    // out that window instead of failing the connection outright.
+    const ERROR_PIPE_BUSY: i32 = 231;
+    const RETRIES: u32 = 5;
+
+    let mut attempt = 0;
+    loop {
+        match ClientOptions::new().open(Path::new(path)) {
+            Ok(client) => return Ok(client),
+            Err(ref err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY) && attempt < RETRIES => {
+                attempt += 1;
+                thread::sleep(StdDuration::from_millis(200));
+            }
+            Err(err) => return Err(format!("{}", err)),
+        }
+    }
+}
+
+/// TLS options for `wss://` connections. Without `ca_file` set, the default
+/// webpki root store is used, same as a browser would.
+#[derive(Default, Clone)]
+pub struct TlsConfig {
+    /// A PEM file of root certificates to trust, for servers behind a
+    /// private CA. When set, these replace the default webpki roots rather
+    /// than being added to them.
+    pub ca_file: Option<PathBuf>,
+    /// A client certificate/key pair for mutual TLS, both PEM encoded.
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    /// Accept any server certificate, including self-signed/expired ones.
+    /// Only meant for lab setups, never for anything reachable from the
+    /// internet.
+    pub danger_accept_invalid_certs: bool,
+}
+
+struct NoCertVerification;
+
+impl rustls::ServerCertVerifier for NoCertVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+fn load_certs(path: &PathBuf) -> Result<Vec<Certificate>, String> {
+    let f = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    certs(&mut BufReader::new(f)).map_err(|()| format!("{}: invalid certificate", path.display()))
+}
+
+fn load_key(path: &PathBuf) -> Result<PrivateKey, String> {
+    // `openssl genpkey`/`certbot` output PKCS#8 (or EC) keys by default, not
+    // the PKCS#1 `rsa_private_keys` alone understands, so a PEM file is
+    // re-read against both parsers rather than assuming RSA.
+    let f = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut keys = rsa_private_keys(&mut BufReader::new(f))
+        .map_err(|()| format!("{}: invalid private key", path.display()))?;
+    if keys.is_empty() {
+        let f = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        keys = pkcs8_private_keys(&mut BufReader::new(f))
+            .map_err(|()| format!("{}: invalid private key", path.display()))?;
+    }
+    keys.pop()
+        .ok_or_else(|| format!("{}: no private key found", path.display()))
+}
+
+fn build_tls_config(tls: &TlsConfig) -> Result<Arc<ClientConfig>, String> {
+    let mut config = ClientConfig::new();
+
+    match tls.ca_file {
+        Some(ref path) => {
+            let f = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            config
+                .root_store
+                .add_pem_file(&mut BufReader::new(f))
+                .map_err(|()| format!("{}: invalid CA certificate", path.display()))?;
+        }
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+
+    if let Some((ref cert, ref key)) = tls.client_cert {
+        config
+            .set_single_client_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|err| format!("{}", err))?;
+    }
+
+    if tls.danger_accept_invalid_certs {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertVerification));
+    }
+
+    Ok(Arc::new(config))
+}
+
+/// Client-initiated keepalive settings. A silently dropped connection (e.g.
+/// laptop sleep, a NAT timeout) otherwise leaves the worker blocked on the
+/// stream forever with no data and no error.
+#[derive(Clone)]
+pub struct HeartbeatConfig {
+    /// How long the connection may sit idle before a `Ping` is sent.
+    pub interval: Duration,
+    /// How long to wait for any reply after sending a `Ping` before the
+    /// connection is considered dead.
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Commands accepted by the background `RpcWorker` task.
+pub enum Command {
+    /// (Re)connect to a server, replacing any existing connection.
+    Connect(Url, String),
+    /// A fire-and-forget outbound message.
+    Message(CMessage),
+    /// An outbound message whose reply is delivered through the given
+    /// one-shot sender instead of `View::handle_rpc`, keyed by `serial`
+    /// (which `send_await` has already stamped into the message itself).
+    /// See `send_await`.
+    Await(CMessage, u64, oneshot::Sender<SMessage>),
+    /// Disconnect and stop the worker's run loop.
+    Shutdown,
+}
+
+/// Rewrites `msg`'s `serial` field to `serial`, so the wire message always
+/// carries the exact id its reply is tracked under, no matter what (if
+/// anything) the caller set it to. Falls back to `msg` unchanged on the
+/// serialization round-trip failing, which shouldn't happen for a well-formed
+/// `CMessage`.
+fn stamp_serial(msg: CMessage, serial: u64) -> CMessage {
+    let stamped = serde_json::to_value(&msg).ok().and_then(|mut v| {
+        v.as_object_mut()?
+            .insert("serial".to_owned(), serde_json::Value::from(serial));
+        serde_json::from_value(v).ok()
+    });
+    stamped.unwrap_or(msg)
+}
+
+/// Cheap, freely cloneable handle to the RPC connection. Every method here
+/// just funnels a `Command` to the `RpcWorker` task that owns the socket, so
+/// unlike the old design there's no `RefCell`/`Mutex` to guard and no need
+/// for an `unsafe impl Send`/`Sync`.
+#[derive(Clone)]
+pub struct RpcContext {
+    cmd_tx: UnboundedSender<Command>,
     // FIXME: Once feature `integer atomics` lands, switch to AtomicU64
-    serial: AtomicUsize,
-    core: RefCell<Core>,
-    view: &'v View,
+    serial: Arc<AtomicUsize>,
+}
+
+impl RpcContext {
+    /// Creates a handle/worker pair. The worker owns the actual socket and
+    /// must be driven by calling `RpcWorker::run` on a dedicated thread.
+    /// `tls` configures `wss://` connections; `TlsConfig::default()` gets
+    /// you the standard webpki root store. `heartbeat` configures the
+    /// client-initiated keepalive; `HeartbeatConfig::default()` is fine for
+    /// most setups.
+    pub fn new(
+        view: &View,
+        tls: TlsConfig,
+        heartbeat: HeartbeatConfig,
+    ) -> (RpcContext, RpcWorker) {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded();
+        let serial = Arc::new(AtomicUsize::new(0));
+        let ctx = RpcContext {
+            cmd_tx,
+            serial: serial.clone(),
+        };
+        let worker = RpcWorker::new(view, ctx.clone(), cmd_rx, tls, heartbeat);
+        (ctx, worker)
+    }
+
+    pub fn next_serial(&self) -> u64 {
+        self.serial.fetch_add(1, Ordering::AcqRel) as _
+    }
+
+    /// Asks the worker to (re)connect. Returns once the request has been
+    /// queued, not once the connection is established; connection failures
+    /// are surfaced through `View::global_err`.
+    pub fn init(&self, srv: Url, pass: &str) -> Result<(), String> {
+        Transport::parse(&srv)?;
+        let _ = self.cmd_tx
+            .unbounded_send(Command::Connect(srv, pass.to_owned()));
+        Ok(())
+    }
+
+    pub fn send(&self, msg: CMessage) {
+        let _ = self.cmd_tx.unbounded_send(Command::Message(msg));
+    }
+
+    /// Sends `msg` and returns a future that resolves with the `SMessage`
+    /// the server answers it with. Allocates the serial `msg` is correlated
+    /// by itself (stamping it into `msg`, overwriting whatever was there),
+    /// so the caller doesn't need to set one. The future resolves with an
+    /// error if the connection dies before a reply arrives.
+    pub fn send_await(&self, msg: CMessage) -> oneshot::Receiver<SMessage> {
+        let serial = self.next_serial();
+        let msg = stamp_serial(msg, serial);
+        let (tx, rx) = oneshot::channel();
+        let _ = self.cmd_tx
+            .unbounded_send(Command::Await(msg, serial, tx));
+        rx
+    }
+
+    pub fn shutdown(&self) {
+        let _ = self.cmd_tx.unbounded_send(Command::Shutdown);
+    }
+}
+
+enum Event {
+    Socket(OwnedMessage),
+    Cmd(Command),
+    // Fired at a fixed, fine-grained resolution; the heartbeat state machine
+    // in `drive` uses it to both decide when to send a `Ping` and when a
+    // missed `Pong` should be treated as a dead connection.
+    HeartbeatTick,
+}
+
+enum Disconnected {
+    Shutdown,
+    ConnectionLost,
+    // A `Command::Connect` arrived while already connected; per its doc this
+    // tears down the live connection and connects to the given server
+    // instead, bypassing the backoff `reconnect` uses for unplanned drops.
+    Reconnect(Url, String),
+}
+
+// What the select loop in `drive` should do once it stops iterating events;
+// set from within the `for_each` closure, which only holds `&mut` borrows of
+// individual `RpcWorker` fields rather than `self`.
+enum DriveOutcome {
+    ConnectionLost,
+    Shutdown,
+    Reconnect(Url, String),
+}
+
+fn write_raw(sink: &mut BoxedSink, msg: OwnedMessage) -> Result<(), String> {
+    sink.start_send(msg).map_err(|err| format!("{:?}", err))?;
+    sink.poll_complete().map_err(|err| format!("{:?}", err))?;
+    Ok(())
 }
 
-unsafe impl<'v> Send for RpcContext<'v> {}
-unsafe impl<'v> Sync for RpcContext<'v> {}
+fn dispatch_out(subscribed: &mut HashSet<String>, sink: &mut BoxedSink, view: &View, msg: CMessage) {
+    match msg {
+        CMessage::Subscribe { ref ids, .. } => {
+            subscribed.extend(ids.iter().cloned());
+        }
+        CMessage::Unsubscribe { ref ids, .. } => {
+            for id in ids {
+                subscribed.remove(id);
+            }
+        }
+        _ => {}
+    }
+
+    match serde_json::to_string(&msg) {
+        Err(e) => view.global_err(format!("{}", e.description())),
+        Ok(s) => if let Err(e) = write_raw(sink, OwnedMessage::Text(s)) {
+            view.global_err(e);
+        },
+    }
+}
+
+/// Owns the socket and drives it on a single-threaded `Core`. The rest of
+/// the program never touches the socket directly, it only ever talks to an
+/// `RpcContext` handle that funnels commands through a channel; the worker's
+/// select loop merges the inbound socket stream with that command channel.
+pub struct RpcWorker<'v> {
+    view: &'v View,
+    ctx: RpcContext,
+    core: Core,
+    cmd_rx: UnboundedReceiver<Command>,
+    pending: FxHashMap<u64, oneshot::Sender<SMessage>>,
+    // Resource ids the UI currently wants updates for. Replayed against the
+    // server every time a dropped connection is re-established.
+    subscribed: HashSet<String>,
+    // The transport/password last connected with, kept around so a dead
+    // connection can be re-established without the caller noticing.
+    conn_info: Option<(Url, String)>,
+    tls: TlsConfig,
+    heartbeat: HeartbeatConfig,
+    // `unix://`/`pipe://` have no Ping/Pong framing of their own (see
+    // `box_raw_conn`), so a server `Pong` can never arrive on them; the
+    // client-initiated heartbeat only makes sense over WebSocket.
+    heartbeat_capable: bool,
+}
 
-impl<'v> RpcContext<'v> {
-    pub fn new(view: &'v View) -> RpcContext<'v> {
-        RpcContext {
-            socket: RefCell::new(None),
-            waiter: {
-                let (s, r) = mpsc::channel(1);
-                (RefCell::new(s), RefCell::new(r))
-            },
-            serial: AtomicUsize::new(0),
-            core: RefCell::new(Core::new().unwrap()),
+impl<'v> RpcWorker<'v> {
+    fn new(
+        view: &'v View,
+        ctx: RpcContext,
+        cmd_rx: UnboundedReceiver<Command>,
+        tls: TlsConfig,
+        heartbeat: HeartbeatConfig,
+    ) -> RpcWorker<'v> {
+        RpcWorker {
             view,
+            ctx,
+            core: Core::new().unwrap(),
+            cmd_rx,
+            pending: FxHashMap::default(),
+            subscribed: HashSet::new(),
+            conn_info: None,
+            tls,
+            heartbeat,
+            heartbeat_capable: true,
         }
     }
 
-    pub fn init(&self, mut srv: Url, pass: &str) -> Result<(), String> {
-        let url = srv.query_pairs_mut().append_pair("password", pass).finish();
-        let (sink, mut stream) = {
-            let mut core = self.core.borrow_mut();
-            let timeout = Timeout::new(Duration::from_secs(10), &core.handle()).unwrap();
-            let fut = ClientBuilder::new(url.as_str())
-                .map_err(|err| format!("{}", err))?
-                .async_connect(None, &core.handle())
-                .map_err(|err| format!("{:?}", err))
-                .select2(timeout.map(|_| "Timeout while connecting to server (10s)".to_owned()));
-            match core.run(fut) {
-                Ok(Either::A(((client, _), _))) => client.split(),
-                Ok(Either::B((err, _))) | Err(Either::A((err, _))) => {
-                    return Err(err);
+    /// Drives the connection, reconnecting with backoff across transient
+    /// drops, until a `Command::Shutdown` is received. Blocks the calling
+    /// thread; spawn this onto its own thread.
+    pub fn run(mut self) {
+        'connect: loop {
+            let (srv, pass) = match self.next_connect() {
+                Some(creds) => creds,
+                None => return,
+            };
+
+            let (mut sink, mut stream) = match self.connect(srv, pass) {
+                Ok(pair) => pair,
+                Err(e) => {
+                    self.view.global_err(e);
+                    continue 'connect;
+                }
+            };
+
+            loop {
+                match self.drive(sink, stream) {
+                    Disconnected::Shutdown => return,
+                    Disconnected::ConnectionLost => {
+                        if !::RUNNING.load(Ordering::Acquire) {
+                            return;
+                        }
+                        match self.reconnect() {
+                            Ok(pair) => {
+                                sink = pair.0;
+                                stream = pair.1;
+                            }
+                            Err(()) => return,
+                        }
+                    }
+                    Disconnected::Reconnect(srv, pass) => {
+                        // A fresh, user-requested connect, not a dropped one:
+                        // attempt it once, the same way the very first
+                        // connection is attempted, rather than via the
+                        // backoff loop `reconnect` uses for unplanned drops.
+                        match self.connect(srv, pass) {
+                            Ok(pair) => {
+                                sink = pair.0;
+                                stream = pair.1;
+                            }
+                            Err(e) => {
+                                self.view.global_err(e);
+                                continue 'connect;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Blocks waiting for the first `Command::Connect`; there is no socket
+    /// yet, so any `Message`/`Await` commands received in the meantime are
+    /// simply dropped.
+    fn next_connect(&mut self) -> Option<(Url, String)> {
+        loop {
+            match self.cmd_rx.by_ref().wait().next() {
+                Some(Ok(Command::Connect(srv, pass))) => return Some((srv, pass)),
+                Some(Ok(Command::Shutdown)) | None => return None,
+                Some(Ok(_)) | Some(Err(_)) => continue,
+            }
+        }
+    }
+
+    /// Connects a `wss://` URL over rustls instead of leaving TLS to
+    /// whatever the `websocket` crate defaults to, so a custom CA/client
+    /// cert (see `TlsConfig`) can be used. The TCP connect and TLS
+    /// handshake are done by hand, then handed to the websocket client as
+    /// an already-secured stream.
+    fn connect_wss(&mut self, url: Url) -> Result<(BoxedSink, BoxedStream), String> {
+        let host = url.host_str()
+            .ok_or_else(|| "wss:// URL is missing a host".to_owned())?
+            .to_owned();
+        let port = url.port_or_known_default().unwrap_or(443);
+        let addr = (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|err| format!("{}", err))?
+            .next()
+            .ok_or_else(|| format!("could not resolve {}", host))?;
+
+        let tcp = self.core
+            .run(TcpStream::connect(&addr, &self.core.handle()))
+            .map_err(|err| format!("{}", err))?;
+
+        let dns_name = DNSNameRef::try_from_ascii_str(&host)
+            .map_err(|_| format!("{}: not a valid DNS name", host))?;
+        let tls_config = build_tls_config(&self.tls)?;
+        let tls = self.core
+            .run(RustlsConnector::from(tls_config).connect(dns_name, tcp))
+            .map_err(|err| format!("{}", err))?;
+
+        let (client, _) = self.core
+            .run(
+                ClientBuilder::new(url.as_str())
+                    .map_err(|err| format!("{}", err))?
+                    .async_connect_on(tls),
+            )
+            .map_err(|err| format!("{:?}", err))?;
+
+        let (sink, stream) = client.split();
+        let sink: BoxedSink = Box::new(sink.sink_map_err(|err| format!("{:?}", err)));
+        let stream: BoxedStream = Box::new(stream.map_err(|err| format!("{:?}", err)));
+        Ok((sink, stream))
+    }
+
+    fn connect(&mut self, srv: Url, pass: String) -> Result<(BoxedSink, BoxedStream), String> {
+        let transport = Transport::parse(&srv)?;
+        self.conn_info = Some((srv, pass.clone()));
+        self.heartbeat_capable = match transport {
+            Transport::WebSocket(_) => true,
+            #[cfg(unix)]
+            Transport::Unix(_) => false,
+            #[cfg(windows)]
+            Transport::Pipe(_) => false,
+        };
+
+        let (sink, mut stream) = match transport {
+            Transport::WebSocket(ref url) if url.scheme() == "wss" => {
+                let mut url = url.clone();
+                let url = url.query_pairs_mut().append_pair("password", &pass).finish();
+                self.connect_wss(url.clone())?
+            }
+            Transport::WebSocket(mut url) => {
+                let url = url.query_pairs_mut().append_pair("password", &pass).finish();
+                let timeout = Timeout::new(Duration::from_secs(10), &self.core.handle()).unwrap();
+                let fut = ClientBuilder::new(url.as_str())
+                    .map_err(|err| format!("{}", err))?
+                    .async_connect(None, &self.core.handle())
+                    .map_err(|err| format!("{:?}", err))
+                    .select2(timeout.map(|_| "Timeout while connecting to server (10s)".to_owned()));
+                match self.core.run(fut) {
+                    Ok(Either::A(((client, _), _))) => {
+                        let (sink, stream) = client.split();
+                        let sink: BoxedSink = Box::new(sink.sink_map_err(|err| format!("{:?}", err)));
+                        let stream: BoxedStream =
+                            Box::new(stream.map_err(|err| format!("{:?}", err)));
+                        (sink, stream)
+                    }
+                    Ok(Either::B((err, _))) | Err(Either::A((err, _))) => {
+                        return Err(err);
+                    }
+                    _ => unreachable!(),
                 }
-                _ => unreachable!(),
+            }
+            // `pass` is intentionally not sent here: unlike `ws(s)://`,
+            // these transports are local-only and already access-controlled
+            // by filesystem permissions (the socket's mode/owner) or, on
+            // Windows, the pipe's security descriptor, so there's no
+            // password handshake to perform.
+            #[cfg(unix)]
+            Transport::Unix(path) => {
+                let handle = self.core.handle();
+                let conn = self.core
+                    .run(UnixStream::connect(&path, &handle))
+                    .map_err(|err| format!("{}", err))?;
+                box_raw_conn(conn)
+            }
+            #[cfg(windows)]
+            Transport::Pipe(path) => {
+                let conn = connect_pipe(&path)?;
+                box_raw_conn(conn)
             }
         };
 
@@ -115,113 +668,235 @@ impl<'v> RpcContext<'v> {
             return Err("Server sent non-text response, i.e. not its version".to_owned());
         }
 
-        *self.socket.borrow_mut() = Some((RefCell::new(stream), Mutex::new(sink.wait())));
-        self.wake();
-        Ok(())
+        Ok((sink, stream))
     }
 
-    pub fn wake(&self) {
-        self.waiter.0.borrow_mut().try_send(()).unwrap();
-    }
-
-    pub fn next_serial(&self) -> u64 {
-        self.serial.fetch_add(1, Ordering::AcqRel) as _
-    }
-
-    pub fn send(&self, msg: CMessage) {
-        match serde_json::to_string(&msg) {
-            Err(e) => self.view.global_err(format!("{}", e.description())),
-            Ok(msg) => self.send_raw(OwnedMessage::Text(msg)),
-        }
-    }
-
-    fn send_raw(&self, msg: OwnedMessage) {
-        let sink = self.socket.borrow();
-        let sink = sink.as_ref();
-        let mut sink = sink.unwrap().1.lock();
+    /// Runs the select loop for one connection's lifetime: merges inbound
+    /// socket frames with outbound commands until the socket dies or a
+    /// `Command::Shutdown` arrives.
+    fn drive(&mut self, sink: BoxedSink, stream: BoxedStream) -> Disconnected {
+        let mut sink = sink;
+        let mut outcome = DriveOutcome::ConnectionLost;
 
-        match (sink.send(msg), sink.flush()) {
-            (Err(e), _) | (_, Err(e)) => self.view.global_err(format!("{:?}", e)),
-            _ => {}
+        // Replay any tracked subscriptions against the freshly (re)connected
+        // socket, now that there's a `Core` task driving `core.run` to
+        // register a waker with if the flush would block; `reconnect` can't
+        // safely do this itself, see the comment inside it.
+        if !self.subscribed.is_empty() {
+            let ids: Vec<_> = self.subscribed.iter().cloned().collect();
+            let serial = self.ctx.next_serial();
+            let msg = CMessage::Subscribe { serial, ids };
+            match serde_json::to_string(&msg) {
+                Err(e) => self.view.global_err(format!("{}", e.description())),
+                Ok(s) => match self.core.run(sink.send(OwnedMessage::Text(s))) {
+                    Ok(returned) => sink = returned,
+                    Err(e) => {
+                        self.view.global_err(e);
+                        return Disconnected::ConnectionLost;
+                    }
+                },
+            }
         }
-    }
 
-    pub fn recv_until_death(&self) {
-        // Each iteration represents the lifetime of a connection to a server
-        loop {
-            // Wait for initialization
-            let mut waiter = self.waiter.1.borrow_mut();
-            let _ = waiter.by_ref().wait().next().unwrap();
-
-            // Check if exited before login
-            let socket = self.socket.borrow();
-            if socket.is_none() {
-                return;
-            }
+        // Tracks the heartbeat state machine: `last_activity` is bumped on
+        // every inbound frame, `ping_sent_at` is set when we send a `Ping`
+        // while idle and cleared by the next inbound frame; if it's still
+        // set once `heartbeat.timeout` has passed, the connection is dead.
+        let mut last_activity = Instant::now();
+        let mut ping_sent_at: Option<Instant> = None;
+        // A fine-grained tick so the single-timer state machine above can
+        // notice both "time to ping" and "ping timed out" without needing a
+        // second, dynamically-reset timer merged into the select loop.
+        let tick = cmp::min(self.heartbeat.interval, self.heartbeat.timeout) / 4;
+        let heartbeat_interval = self.heartbeat.interval;
+        let heartbeat_timeout = self.heartbeat.timeout;
+        let heartbeat_capable = self.heartbeat_capable;
 
-            let mut core = self.core.borrow_mut();
-            let socket = socket.as_ref().unwrap();
-            let mut stream = socket.0.borrow_mut();
+        {
+            let view = self.view;
+            let ctx = &self.ctx;
+            let pending = &mut self.pending;
+            let subscribed = &mut self.subscribed;
+            let outcome = &mut outcome;
 
-            let msg_handler = stream
-                .by_ref()
-                .map(|msg| StreamRes::Msg(msg))
+            let events = stream
+                .map(Event::Socket)
                 .map_err(|err| format!("{:?}", err))
                 .select(
-                    waiter
+                    self.cmd_rx
                         .by_ref()
-                        .map(|_| StreamRes::Close)
-                        .map_err(|err| format!("{:?}", err)),
+                        .map(Event::Cmd)
+                        .map_err(|()| "command channel closed".to_owned()),
                 )
-                .or_else(|e| future::err(self.view.global_err(e)))
-                .and_then(|res| match res {
-                    StreamRes::Msg(msg) => match msg {
-                        OwnedMessage::Ping(p) => {
-                            self.send_raw(OwnedMessage::Pong(p));
-                            future::ok(())
+                .select(
+                    Interval::new(Instant::now() + tick, tick)
+                        .map(|_| Event::HeartbeatTick)
+                        .map_err(|err| format!("{}", err)),
+                )
+                .or_else(|e| future::err(view.global_err(e)))
+                .for_each(|event| {
+                    if let Event::Socket(_) = event {
+                        last_activity = Instant::now();
+                        ping_sent_at = None;
+                    }
+
+                    match event {
+                        Event::Socket(OwnedMessage::Ping(p)) => {
+                            let _ = write_raw(&mut sink, OwnedMessage::Pong(p));
                         }
-                        OwnedMessage::Close(data) => {
-                            self.view.server_close(data);
-                            future::err(())
+                        Event::Socket(OwnedMessage::Pong(_)) => {}
+                        Event::Socket(OwnedMessage::Close(data)) => {
+                            view.server_close(data);
+                            return future::err(());
                         }
-                        OwnedMessage::Text(s) => {
+                        Event::Socket(OwnedMessage::Text(s)) => {
+                            let serial = serde_json::from_str::<serde_json::Value>(&s)
+                                .ok()
+                                .and_then(|v| v.get("serial").and_then(|s| s.as_u64()));
+                            let waiting = serial.and_then(|serial| pending.remove(&serial));
+
                             match serde_json::from_str::<SMessage>(&s) {
-                                Err(e) => self.view.global_err(format!("{}", e.description())),
-                                Ok(msg) => if let SMessage::ResourcesExtant { ref ids, .. } = msg {
+                                Err(e) => view.global_err(format!("{}", e.description())),
+                                Ok(msg) => if let Some(tx) = waiting {
+                                    // A waiting `send_await` caller gets the
+                                    // reply directly; it never reaches
+                                    // `view.handle_rpc`.
+                                    let _ = tx.send(msg);
+                                } else if let SMessage::ResourcesExtant { ref ids, .. } = msg {
                                     let ids: Vec<_> =
                                         ids.iter().map(|id| id.clone().into_owned()).collect();
-
-                                    self.send(CMessage::Subscribe {
-                                        serial: self.next_serial(),
-                                        ids: ids.clone(),
-                                    });
+                                    let serial = ctx.next_serial();
+                                    dispatch_out(
+                                        subscribed,
+                                        &mut sink,
+                                        view,
+                                        CMessage::Subscribe { serial, ids },
+                                    );
                                 } else if let SMessage::ResourcesRemoved { ref ids, .. } = msg {
-                                    self.send(CMessage::Unsubscribe {
-                                        serial: self.next_serial(),
-                                        ids: ids.clone(),
-                                    });
-
-                                    self.view.handle_rpc(self, &msg);
+                                    let ids: Vec<_> =
+                                        ids.iter().map(|id| id.clone().into_owned()).collect();
+                                    let serial = ctx.next_serial();
+                                    dispatch_out(
+                                        subscribed,
+                                        &mut sink,
+                                        view,
+                                        CMessage::Unsubscribe { serial, ids },
+                                    );
+                                    view.handle_rpc(ctx, &msg);
                                 } else {
-                                    self.view.handle_rpc(self, &msg);
+                                    view.handle_rpc(ctx, &msg);
                                 },
                             };
-                            future::ok(())
                         }
-                        _ => unreachable!(),
-                    },
-                    StreamRes::Close => future::err(()),
+                        Event::Socket(_) => unreachable!(),
+                        // Raw transports (`unix://`/`pipe://`) have no
+                        // Ping/Pong of their own, see `heartbeat_capable`; a
+                        // ping sent there would never see a reply and would
+                        // just manufacture spurious reconnects. A dead peer
+                        // is still caught there, just not by this timer:
+                        // `box_raw_conn` turns the stream ending (EOF) into a
+                        // connection-lost error on its own.
+                        Event::HeartbeatTick if !heartbeat_capable => {}
+                        Event::HeartbeatTick => match ping_sent_at {
+                            Some(sent) if Instant::now().duration_since(sent) >= heartbeat_timeout => {
+                                view.global_err(
+                                    "no response to heartbeat, treating connection as dead"
+                                        .to_owned(),
+                                );
+                                return future::err(());
+                            }
+                            Some(_) => {}
+                            None => if Instant::now().duration_since(last_activity)
+                                >= heartbeat_interval
+                            {
+                                let _ = write_raw(&mut sink, OwnedMessage::Ping(Vec::new()));
+                                ping_sent_at = Some(Instant::now());
+                            },
+                        },
+                        Event::Cmd(Command::Shutdown) => {
+                            *outcome = DriveOutcome::Shutdown;
+                            return future::err(());
+                        }
+                        Event::Cmd(Command::Connect(srv, pass)) => {
+                            *outcome = DriveOutcome::Reconnect(srv, pass);
+                            return future::err(());
+                        }
+                        Event::Cmd(Command::Message(msg)) => {
+                            dispatch_out(subscribed, &mut sink, view, msg);
+                        }
+                        Event::Cmd(Command::Await(msg, serial, tx)) => {
+                            pending.insert(serial, tx);
+                            dispatch_out(subscribed, &mut sink, view, msg);
+                        }
+                    }
+                    future::ok(())
                 });
 
-            // Wait until the stream is, or should be, terminated
-            let _ = core.run(msg_handler.for_each(|_| Ok(())));
+            // Wait until the connection is, or should be, terminated
+            let _ = self.core.run(events);
+        }
 
-            if ::RUNNING.load(Ordering::Acquire) {
-                *self.socket.borrow_mut() = None;
-                continue;
-            } else {
-                break;
+        // Dropping the senders completes the corresponding `send_await`
+        // futures with `Canceled` rather than leaving them hanging forever
+        // on a connection that's gone.
+        self.pending.clear();
+
+        match outcome {
+            DriveOutcome::Shutdown => Disconnected::Shutdown,
+            DriveOutcome::ConnectionLost => Disconnected::ConnectionLost,
+            DriveOutcome::Reconnect(srv, pass) => {
+                // The new server may track a different set of resources
+                // entirely; let `ResourcesExtant` re-populate this once the
+                // new connection is up rather than replaying stale ids.
+                self.subscribed.clear();
+                Disconnected::Reconnect(srv, pass)
             }
         }
     }
+
+    /// Repeatedly re-runs the connect handshake against the last known
+    /// server with exponential backoff until it succeeds or the program is
+    /// shutting down. `drive` replays the tracked subscriptions against the
+    /// returned connection so the UI's view of the world is restored
+    /// transparently.
+    fn reconnect(&mut self) -> Result<(BoxedSink, BoxedStream), ()> {
+        let (srv, pass) = match self.conn_info.clone() {
+            Some(creds) => creds,
+            None => return Err(()),
+        };
+
+        let mut backoff = Duration::from_millis(500);
+        let max_backoff = Duration::from_secs(30);
+        let mut attempt = 1u32;
+
+        while ::RUNNING.load(Ordering::Acquire) {
+            self.view
+                .global_err(format!("reconnecting (attempt {})", attempt));
+
+            match self.connect(srv.clone(), pass.clone()) {
+                // Subscriptions are replayed in `drive`, inside the `Core`'s
+                // task context, rather than here: flushing a `Sink` needs a
+                // task to register a waker with if it would block, and this
+                // function doesn't run inside `core.run`.
+                Ok(pair) => return Ok(pair),
+                Err(e) => {
+                    self.view.global_err(e);
+                    // A touch of jitter so a fleet of clients that dropped
+                    // at the same instant doesn't hammer the server in
+                    // lockstep. `RandomState`'s keys are seeded from the OS
+                    // RNG on construction, so hashing anything with it is a
+                    // cheap source of real per-process randomness without
+                    // pulling in a dedicated rng crate.
+                    let mut hasher = RandomState::new().build_hasher();
+                    hasher.write_u32(attempt);
+                    let jitter = Duration::from_millis(hasher.finish() % 250);
+                    thread::sleep(backoff + jitter);
+                    backoff = cmp::min(backoff * 2, max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+
+        Err(())
+    }
 }